@@ -21,6 +21,7 @@ use crate::extensions::*;
 use crate::messages::*;
 use crate::schedule::*;
 use crate::treemath::*;
+use std::ops::Deref;
 
 const OUT_OF_ORDER_TOLERANCE: u32 = 5;
 const MAXIMUM_FORWARD_DISTANCE: u32 = 1000;
@@ -30,6 +31,71 @@ pub enum ASError {
     TooDistantInThePast,
     TooDistantInTheFuture,
     IndexOutOfBounds,
+    SecretAlreadyConsumed,
+    WrongEpoch,
+    UnsupportedRemoval,
+    TreeNotInitialized,
+}
+
+/// MLS ratchets handshake and application messages separately, each from its
+/// own secret derived from the leaf node secret, so compromising one
+/// ratchet's state doesn't expose the other content type's keys.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentType {
+    Handshake,
+    Application,
+}
+
+/// Overwrite `buf` with zeroes through a volatile write, so the compiler
+/// cannot optimize the write away even though the memory is about to be
+/// freed or reused.
+fn zeroize(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// A byte buffer holding key material derived somewhere in the secret tree.
+/// Its contents are wiped as soon as it is dropped, so a ratchet secret
+/// never outlives the scope that needed it.
+#[derive(Clone)]
+pub(crate) struct Secret(Vec<u8>);
+
+impl Secret {
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        Secret(bytes)
+    }
+}
+
+impl Deref for Secret {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl Codec for Secret {
+    fn encode(&self, buffer: &mut Vec<u8>) -> Result<(), CodecError> {
+        encode_vec(VecSize::VecU8, buffer, &self.0)?;
+        Ok(())
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let secret = decode_vec(VecSize::VecU8, cursor)?;
+        Ok(Secret::new(secret))
+    }
 }
 
 fn derive_app_secret(
@@ -39,16 +105,16 @@ fn derive_app_secret(
     node: u32,
     generation: u32,
     length: usize,
-) -> Vec<u8> {
+) -> Secret {
     let application_context = ApplicationContext { node, generation };
     let serialized_application_context = application_context.encode_detached().unwrap();
-    hkdf_expand_label(
+    Secret::new(hkdf_expand_label(
         ciphersuite,
         secret,
         label,
         &serialized_application_context,
         length,
-    )
+    ))
 }
 
 #[derive(Debug, PartialEq)]
@@ -77,16 +143,16 @@ impl Codec for ApplicationContext {
 
 #[derive(Clone)]
 struct ASTreeNode {
-    pub secret: Vec<u8>,
+    pub secret: Secret,
 }
 
 impl Codec for ASTreeNode {
     fn encode(&self, buffer: &mut Vec<u8>) -> Result<(), CodecError> {
-        encode_vec(VecSize::VecU8, buffer, &self.secret)?;
+        self.secret.encode(buffer)?;
         Ok(())
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let secret = decode_vec(VecSize::VecU8, cursor)?;
+        let secret = Secret::decode(cursor)?;
         Ok(ASTreeNode { secret })
     }
 }
@@ -95,7 +161,16 @@ struct SenderRatchet {
     ciphersuite: CipherSuite,
     index: RosterIndex,
     generation: u32,
-    past_secrets: Vec<Vec<u8>>,
+    // `past_secrets[i]` holds the secret for generation
+    // `self.generation - (past_secrets.len() - 1 - i)`, or `None` if that
+    // secret has already been consumed by `get_secret` or erased by
+    // `delete_secret`. The last entry (the "tip") is the one exception: it
+    // doubles as the ratchet's forward state and is never erased while it
+    // is still the tip, since every later generation is derived from it.
+    // Advancing the ratchet does not erase the former tip either: it stays
+    // `Some` until it is evicted from the window or erased by
+    // `delete_secret`, even if its key/nonce pair was already derived.
+    past_secrets: Vec<Option<Secret>>,
 }
 
 impl Codec for SenderRatchet {
@@ -103,23 +178,14 @@ impl Codec for SenderRatchet {
         self.ciphersuite.encode(buffer)?;
         self.index.as_u32().encode(buffer)?;
         self.generation.encode(buffer)?;
-        let len = self.past_secrets.len();
-        (len as u32).encode(buffer)?;
-        for i in 0..len {
-            encode_vec(VecSize::VecU8, buffer, &self.past_secrets[i])?;
-        }
+        encode_vec(VecSize::VecU32, buffer, &self.past_secrets)?;
         Ok(())
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let ciphersuite = CipherSuite::decode(cursor)?;
         let index = RosterIndex::from(u32::decode(cursor)?);
         let generation = u32::decode(cursor)?;
-        let len = u32::decode(cursor)? as usize;
-        let mut past_secrets = vec![];
-        for _ in 0..len {
-            let secret = decode_vec(VecSize::VecU8, cursor)?;
-            past_secrets.push(secret);
-        }
+        let past_secrets = decode_vec(VecSize::VecU32, cursor)?;
         Ok(SenderRatchet {
             ciphersuite,
             index,
@@ -135,7 +201,7 @@ impl SenderRatchet {
             ciphersuite,
             index,
             generation: 0,
-            past_secrets: vec![secret.to_vec()],
+            past_secrets: vec![Some(Secret::new(secret.to_vec()))],
         }
     }
     pub fn get_secret(&mut self, generation: u32) -> Result<ApplicationSecrets, ASError> {
@@ -149,24 +215,59 @@ impl SenderRatchet {
         if generation <= self.generation {
             let window_index =
                 (self.past_secrets.len() as u32 - (self.generation - generation) - 1) as usize;
-            let secret = self.past_secrets.get(window_index).unwrap().clone();
+            let is_tip = window_index + 1 == self.past_secrets.len();
+            let secret = match &self.past_secrets[window_index] {
+                Some(secret) => secret.clone(),
+                None => return Err(ASError::SecretAlreadyConsumed),
+            };
             let application_secrets = self.derive_key_nonce(&secret, generation);
+            if !is_tip {
+                // This generation's key/nonce pair has now been derived and
+                // will never be needed again, so forward secrecy requires
+                // erasing it immediately rather than waiting for it to age
+                // out of the window.
+                self.past_secrets[window_index] = None;
+            }
             Ok(application_secrets)
         } else {
             for _ in 0..(generation - self.generation) {
                 if self.past_secrets.len() == OUT_OF_ORDER_TOLERANCE as usize {
                     self.past_secrets.remove(0);
                 }
-                let new_secret = self.ratchet_secret(self.past_secrets.last().unwrap());
-                self.past_secrets.push(new_secret);
+                let previous = self
+                    .past_secrets
+                    .last()
+                    .unwrap()
+                    .as_ref()
+                    .expect("the tip of the ratchet is never erased");
+                let new_secret = self.ratchet_secret(previous);
+                self.past_secrets.push(Some(new_secret));
             }
-            let secret = self.past_secrets.last().unwrap();
+            let secret = self.past_secrets.last().unwrap().as_ref().unwrap().clone();
             let application_secrets = self.derive_key_nonce(&secret, generation);
             self.generation = generation;
             Ok(application_secrets)
         }
     }
-    fn ratchet_secret(&self, secret: &[u8]) -> Vec<u8> {
+    /// Erase the secret for `generation` ahead of the normal window eviction,
+    /// e.g. once the application has finished decrypting the corresponding
+    /// message. A no-op if the secret is already gone or is the ratchet's
+    /// tip, since every later generation is derived from the tip; once the
+    /// ratchet has advanced past it, it can be erased here like any other.
+    pub fn delete_secret(&mut self, generation: u32) {
+        if generation > self.generation
+            || (self.generation - generation) >= self.past_secrets.len() as u32
+        {
+            return;
+        }
+        let window_index =
+            (self.past_secrets.len() as u32 - (self.generation - generation) - 1) as usize;
+        if window_index + 1 == self.past_secrets.len() {
+            return;
+        }
+        self.past_secrets[window_index] = None;
+    }
+    fn ratchet_secret(&self, secret: &[u8]) -> Secret {
         let hash_len = hash_length(self.ciphersuite.into());
         derive_app_secret(
             self.ciphersuite,
@@ -201,37 +302,58 @@ impl SenderRatchet {
     }
 }
 
+/// `add_member`/`remove_member` resize the tree in place for a tail-only
+/// roster change without bumping `self.epoch`, blanking the affected
+/// member's direct path (including the root). Members that already have
+/// ratchets keep them and can go on requesting secrets. Nodes off the
+/// blanked path are left as they are, so a leaf without ratchets can still
+/// be derived if its own direct path reaches a populated node; if none is
+/// populated, `get_secret` reports `ASError::TreeNotInitialized` until
+/// `reinit` reseeds the tree with the new epoch's encryption secret.
 pub struct ASTree {
     ciphersuite: CipherSuite,
+    epoch: GroupEpoch,
     nodes: Vec<Option<ASTreeNode>>,
-    sender_ratchets: Vec<Option<SenderRatchet>>,
+    handshake_ratchets: Vec<Option<SenderRatchet>>,
+    application_ratchets: Vec<Option<SenderRatchet>>,
     size: RosterIndex,
 }
 
 impl Codec for ASTree {
     fn encode(&self, buffer: &mut Vec<u8>) -> Result<(), CodecError> {
         self.ciphersuite.encode(buffer)?;
+        self.epoch.encode(buffer)?;
         encode_vec(VecSize::VecU32, buffer, &self.nodes)?;
-        encode_vec(VecSize::VecU32, buffer, &self.sender_ratchets)?;
+        encode_vec(VecSize::VecU32, buffer, &self.handshake_ratchets)?;
+        encode_vec(VecSize::VecU32, buffer, &self.application_ratchets)?;
         self.size.as_u32().encode(buffer)?;
         Ok(())
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let ciphersuite = CipherSuite::decode(cursor)?;
+        let epoch = GroupEpoch::decode(cursor)?;
         let nodes = decode_vec(VecSize::VecU32, cursor)?;
-        let sender_ratchets = decode_vec(VecSize::VecU32, cursor)?;
+        let handshake_ratchets = decode_vec(VecSize::VecU32, cursor)?;
+        let application_ratchets = decode_vec(VecSize::VecU32, cursor)?;
         let size = RosterIndex::from(u32::decode(cursor)?);
         Ok(ASTree {
             ciphersuite,
+            epoch,
             nodes,
-            sender_ratchets,
+            handshake_ratchets,
+            application_ratchets,
             size,
         })
     }
 }
 
 impl ASTree {
-    pub fn new(ciphersuite: CipherSuite, application_secret: &[u8], size: RosterIndex) -> Self {
+    pub fn new(
+        ciphersuite: CipherSuite,
+        epoch: GroupEpoch,
+        application_secret: &[u8],
+        size: RosterIndex,
+    ) -> Self {
         let root = root(size);
         let num_indices = TreeIndex::from(size).as_usize() - 1;
         let mut nodes: Vec<Option<ASTreeNode>> = Vec::with_capacity(num_indices);
@@ -239,61 +361,222 @@ impl ASTree {
             nodes.push(None);
         }
         nodes[root.as_usize()] = Some(ASTreeNode {
-            secret: application_secret.to_vec(),
+            secret: Secret::new(application_secret.to_vec()),
         });
-        let mut sender_ratchets: Vec<Option<SenderRatchet>> = Vec::with_capacity(size.as_usize());
+        let mut handshake_ratchets: Vec<Option<SenderRatchet>> =
+            Vec::with_capacity(size.as_usize());
+        let mut application_ratchets: Vec<Option<SenderRatchet>> =
+            Vec::with_capacity(size.as_usize());
         for _ in 0..(size.as_usize()) {
-            sender_ratchets.push(None);
+            handshake_ratchets.push(None);
+            application_ratchets.push(None);
         }
         Self {
             ciphersuite,
+            epoch,
             nodes,
-            sender_ratchets,
+            handshake_ratchets,
+            application_ratchets,
             size,
         }
     }
-    pub fn get_generation(&self, sender: RosterIndex) -> u32 {
-        if let Some(sender_ratchet) = &self.sender_ratchets[sender.as_usize()] {
+    /// Re-key the tree for `epoch`, consuming the per-epoch encryption
+    /// secret produced by the key schedule (see `crate::schedule`). The
+    /// previous tree's nodes and ratchets are replaced outright, which
+    /// drops and zeroizes all of their secrets.
+    pub fn reinit(&mut self, encryption_secret: &[u8], size: RosterIndex, epoch: GroupEpoch) {
+        *self = ASTree::new(self.ciphersuite, epoch, encryption_secret, size);
+    }
+    pub fn epoch(&self) -> GroupEpoch {
+        self.epoch
+    }
+    pub fn get_generation(&self, content_type: ContentType, sender: RosterIndex) -> u32 {
+        let ratchets = self.ratchets(content_type);
+        if let Some(sender_ratchet) = &ratchets[sender.as_usize()] {
             sender_ratchet.generation
         } else {
             0
         }
     }
+    /// Let an application that has finished decrypting a message erase the
+    /// corresponding ratchet secret ahead of the normal window eviction. A
+    /// no-op if `sender` has no ratchet yet or the secret is already gone.
+    pub fn delete_secret(
+        &mut self,
+        content_type: ContentType,
+        sender: RosterIndex,
+        generation: u32,
+    ) {
+        if let Some(Some(sender_ratchet)) =
+            self.ratchets_mut(content_type).get_mut(sender.as_usize())
+        {
+            sender_ratchet.delete_secret(generation);
+        }
+    }
+    pub fn get_handshake_secret(
+        &mut self,
+        epoch: GroupEpoch,
+        index: RosterIndex,
+        generation: u32,
+    ) -> Result<ApplicationSecrets, ASError> {
+        self.get_secret(ContentType::Handshake, epoch, index, generation)
+    }
+    pub fn get_application_secret(
+        &mut self,
+        epoch: GroupEpoch,
+        index: RosterIndex,
+        generation: u32,
+    ) -> Result<ApplicationSecrets, ASError> {
+        self.get_secret(ContentType::Application, epoch, index, generation)
+    }
     pub fn get_secret(
         &mut self,
+        content_type: ContentType,
+        epoch: GroupEpoch,
         index: RosterIndex,
         generation: u32,
     ) -> Result<ApplicationSecrets, ASError> {
-        let index_in_tree = TreeIndex::from(index);
+        if epoch != self.epoch {
+            return Err(ASError::WrongEpoch);
+        }
         if index >= self.size {
             return Err(ASError::IndexOutOfBounds);
         }
-        if let Some(ratchet_opt) = self.sender_ratchets.get_mut(index.as_usize()) {
-            if let Some(ratchet) = ratchet_opt {
-                return ratchet.get_secret(generation);
-            }
+        self.materialize_leaf(index)?;
+        self.ratchets_mut(content_type)[index.as_usize()]
+            .as_mut()
+            .unwrap()
+            .get_secret(generation)
+    }
+    fn ratchets(&self, content_type: ContentType) -> &Vec<Option<SenderRatchet>> {
+        match content_type {
+            ContentType::Handshake => &self.handshake_ratchets,
+            ContentType::Application => &self.application_ratchets,
         }
+    }
+    fn ratchets_mut(&mut self, content_type: ContentType) -> &mut Vec<Option<SenderRatchet>> {
+        match content_type {
+            ContentType::Handshake => &mut self.handshake_ratchets,
+            ContentType::Application => &mut self.application_ratchets,
+        }
+    }
+    /// Derive the leaf node secret if it hasn't been derived yet, split it
+    /// into independent handshake and application secrets, seed a ratchet
+    /// from each, and blank the leaf node secret so it can't be re-derived.
+    /// A no-op once `index` already has ratchets. Fails with
+    /// `ASError::TreeNotInitialized` if neither the leaf node nor any node
+    /// on its direct path up to the root is populated, which can happen once
+    /// `add_member`/`remove_member` has blanked that path and `reinit`
+    /// hasn't reseeded the tree since.
+    fn materialize_leaf(&mut self, index: RosterIndex) -> Result<(), ASError> {
+        if self.handshake_ratchets[index.as_usize()].is_some()
+            || self.application_ratchets[index.as_usize()].is_some()
+        {
+            return Ok(());
+        }
+        let index_in_tree = TreeIndex::from(index);
         let mut dir_path = vec![index_in_tree];
         dir_path.extend(dirpath(index_in_tree, self.size));
         dir_path.push(root(self.size));
         let mut empty_nodes: Vec<TreeIndex> = vec![];
+        let mut found_populated_ancestor = false;
         for n in dir_path {
             empty_nodes.push(n);
             if self.nodes[n.as_usize()].is_some() {
+                found_populated_ancestor = true;
                 break;
             }
         }
+        if !found_populated_ancestor {
+            return Err(ASError::TreeNotInitialized);
+        }
         empty_nodes.remove(0);
         empty_nodes.reverse();
         for n in empty_nodes {
             self.hash_down(n);
         }
-        let node_secret = &self.nodes[index_in_tree.as_usize()].clone().unwrap().secret;
-        let mut sender_ratchet = SenderRatchet::new(index, node_secret, self.ciphersuite);
-        let application_secret = sender_ratchet.get_secret(generation);
+        let hash_len = hash_length(self.ciphersuite.into());
+        let leaf_secret = self.nodes[index_in_tree.as_usize()].clone().unwrap().secret;
+        let handshake_secret = derive_app_secret(
+            self.ciphersuite,
+            &leaf_secret,
+            "handshake",
+            index_in_tree.as_u32(),
+            0,
+            hash_len,
+        );
+        let application_secret = derive_app_secret(
+            self.ciphersuite,
+            &leaf_secret,
+            "application",
+            index_in_tree.as_u32(),
+            0,
+            hash_len,
+        );
         self.nodes[index_in_tree.as_usize()] = None;
-        self.sender_ratchets[index.as_usize()] = Some(sender_ratchet);
-        application_secret
+        self.handshake_ratchets[index.as_usize()] = Some(SenderRatchet::new(
+            index,
+            &handshake_secret,
+            self.ciphersuite,
+        ));
+        self.application_ratchets[index.as_usize()] = Some(SenderRatchet::new(
+            index,
+            &application_secret,
+            self.ciphersuite,
+        ));
+        Ok(())
+    }
+    /// Grow the tree to `new_size` leaves to admit a new member at `index`.
+    /// Every node on the direct path from `index` to the root is blanked, so
+    /// the new member can't be handed a secret that predates it; its
+    /// `get_secret` calls fail with `ASError::TreeNotInitialized` until
+    /// `reinit`. See the `ASTree` struct docs for the effect on other leaves.
+    pub fn add_member(&mut self, index: RosterIndex, new_size: RosterIndex) {
+        let num_indices = TreeIndex::from(new_size).as_usize() - 1;
+        self.nodes.resize_with(num_indices, || None);
+        self.handshake_ratchets
+            .resize_with(new_size.as_usize(), || None);
+        self.application_ratchets
+            .resize_with(new_size.as_usize(), || None);
+        self.size = new_size;
+        self.blank_direct_path(index);
+    }
+    /// Remove the member at `index`, shrinking the tree by one leaf. Only
+    /// the highest remaining leaf (`index == self.size - 1`) can be removed
+    /// this way; there is no primitive here for transplanting a
+    /// `SenderRatchet`'s state to another index, so this is the literal tail
+    /// member, not an arbitrary one the caller has rearranged into place.
+    /// Its ratchets are dropped (zeroizing their state) and every node on
+    /// the direct path from `index` to the root is blanked before the tree
+    /// shrinks, so no stale secret can be reused. Returns
+    /// `Err(ASError::UnsupportedRemoval)` without touching the tree if
+    /// `index` isn't the highest leaf, rather than silently truncating and
+    /// zeroizing an unrelated, still-active member's key material.
+    pub fn remove_member(&mut self, index: RosterIndex) -> Result<(), ASError> {
+        if index.as_u32() + 1 != self.size.as_u32() {
+            return Err(ASError::UnsupportedRemoval);
+        }
+        self.blank_direct_path(index);
+        self.handshake_ratchets[index.as_usize()] = None;
+        self.application_ratchets[index.as_usize()] = None;
+        let new_size = RosterIndex::from(self.size.as_u32() - 1);
+        let num_indices = TreeIndex::from(new_size).as_usize() - 1;
+        self.nodes.truncate(num_indices);
+        self.handshake_ratchets.truncate(new_size.as_usize());
+        self.application_ratchets.truncate(new_size.as_usize());
+        self.size = new_size;
+        Ok(())
+    }
+    fn blank_direct_path(&mut self, leaf: RosterIndex) {
+        let leaf_in_tree = TreeIndex::from(leaf);
+        let mut dir_path = vec![leaf_in_tree];
+        dir_path.extend(dirpath(leaf_in_tree, self.size));
+        dir_path.push(root(self.size));
+        for n in dir_path {
+            if n.as_usize() < self.nodes.len() {
+                self.nodes[n.as_usize()] = None;
+            }
+        }
     }
     fn hash_down(&mut self, index_in_tree: TreeIndex) {
         let hash_len = hash_length(self.ciphersuite.into());
@@ -329,34 +612,175 @@ impl ASTree {
 #[test]
 fn test_boundaries() {
     let ciphersuite = CipherSuite::MLS10_128_HPKEX25519_CHACHA20POLY1305_SHA256_Ed25519;
-    let mut astree = ASTree::new(ciphersuite, &[0u8; 32], RosterIndex::from(2u32));
-    assert!(astree.get_secret(RosterIndex::from(0u32), 0).is_ok());
-    assert!(astree.get_secret(RosterIndex::from(1u32), 0).is_ok());
-    assert!(astree.get_secret(RosterIndex::from(0u32), 1).is_ok());
-    assert!(astree.get_secret(RosterIndex::from(0u32), 1_000).is_ok());
+    let epoch = GroupEpoch::from(0u64);
+    let mut astree = ASTree::new(ciphersuite, epoch, &[0u8; 32], RosterIndex::from(2u32));
+    assert!(astree
+        .get_application_secret(epoch, RosterIndex::from(0u32), 0)
+        .is_ok());
+    assert!(astree
+        .get_application_secret(epoch, RosterIndex::from(1u32), 0)
+        .is_ok());
+    assert!(astree
+        .get_application_secret(epoch, RosterIndex::from(0u32), 1)
+        .is_ok());
+    assert!(astree
+        .get_application_secret(epoch, RosterIndex::from(0u32), 1_000)
+        .is_ok());
     assert_eq!(
-        astree.get_secret(RosterIndex::from(1u32), 1001),
+        astree.get_application_secret(epoch, RosterIndex::from(1u32), 1001),
         Err(ASError::TooDistantInTheFuture)
     );
-    assert!(astree.get_secret(RosterIndex::from(0u32), 996).is_ok());
+    assert!(astree
+        .get_application_secret(epoch, RosterIndex::from(0u32), 996)
+        .is_ok());
     assert_eq!(
-        astree.get_secret(RosterIndex::from(0u32), 995),
+        astree.get_application_secret(epoch, RosterIndex::from(0u32), 995),
         Err(ASError::TooDistantInThePast)
     );
     assert_eq!(
-        astree.get_secret(RosterIndex::from(2u32), 0),
+        astree.get_application_secret(epoch, RosterIndex::from(2u32), 0),
         Err(ASError::IndexOutOfBounds)
     );
-    let mut largetree = ASTree::new(ciphersuite, &[0u8; 32], RosterIndex::from(100_000u32));
-    assert!(largetree.get_secret(RosterIndex::from(0u32), 0).is_ok());
+    let mut largetree = ASTree::new(
+        ciphersuite,
+        epoch,
+        &[0u8; 32],
+        RosterIndex::from(100_000u32),
+    );
     assert!(largetree
-        .get_secret(RosterIndex::from(99_999u32), 0)
+        .get_application_secret(epoch, RosterIndex::from(0u32), 0)
         .is_ok());
     assert!(largetree
-        .get_secret(RosterIndex::from(99_999u32), 1_000)
+        .get_application_secret(epoch, RosterIndex::from(99_999u32), 0)
+        .is_ok());
+    assert!(largetree
+        .get_application_secret(epoch, RosterIndex::from(99_999u32), 1_000)
         .is_ok());
     assert_eq!(
-        largetree.get_secret(RosterIndex::from(100_000u32), 0),
+        largetree.get_application_secret(epoch, RosterIndex::from(100_000u32), 0),
         Err(ASError::IndexOutOfBounds)
     );
 }
+
+#[test]
+fn test_handshake_and_application_ratchets_advance_independently() {
+    let ciphersuite = CipherSuite::MLS10_128_HPKEX25519_CHACHA20POLY1305_SHA256_Ed25519;
+    let epoch = GroupEpoch::from(0u64);
+    let mut astree = ASTree::new(ciphersuite, epoch, &[0u8; 32], RosterIndex::from(2u32));
+    let sender = RosterIndex::from(0u32);
+
+    assert!(astree.get_handshake_secret(epoch, sender, 3).is_ok());
+    assert_eq!(astree.get_generation(ContentType::Handshake, sender), 3);
+    // The application ratchet hasn't been touched yet, so it still reports
+    // its initial generation even though the handshake ratchet advanced.
+    assert_eq!(astree.get_generation(ContentType::Application, sender), 0);
+
+    assert!(astree.get_application_secret(epoch, sender, 1).is_ok());
+    assert_eq!(astree.get_generation(ContentType::Application, sender), 1);
+    // Driving the application ratchet forward leaves the handshake
+    // ratchet's generation untouched.
+    assert_eq!(astree.get_generation(ContentType::Handshake, sender), 3);
+}
+
+#[test]
+fn test_resize_preserves_active_members() {
+    let ciphersuite = CipherSuite::MLS10_128_HPKEX25519_CHACHA20POLY1305_SHA256_Ed25519;
+    let epoch = GroupEpoch::from(0u64);
+    let mut astree = ASTree::new(ciphersuite, epoch, &[0u8; 32], RosterIndex::from(2u32));
+    let member0 = astree
+        .get_application_secret(epoch, RosterIndex::from(0u32), 0)
+        .unwrap();
+
+    // Adding a member blanks the direct path but must not disturb a member
+    // whose ratchet has already been materialized.
+    astree.add_member(RosterIndex::from(2u32), RosterIndex::from(3u32));
+    assert_eq!(
+        astree
+            .get_application_secret(epoch, RosterIndex::from(0u32), 0)
+            .unwrap(),
+        member0
+    );
+
+    // Only the highest leaf can be removed; anything else is rejected
+    // rather than silently truncating a still-active member's state.
+    assert_eq!(
+        astree.remove_member(RosterIndex::from(0u32)),
+        Err(ASError::UnsupportedRemoval)
+    );
+
+    // Removing the actual highest leaf leaves the remaining member able to
+    // keep ratcheting forward.
+    assert!(astree.remove_member(RosterIndex::from(2u32)).is_ok());
+    assert!(astree
+        .get_application_secret(epoch, RosterIndex::from(0u32), 1)
+        .is_ok());
+}
+
+#[test]
+fn test_add_member_requires_reinit_before_use() {
+    let ciphersuite = CipherSuite::MLS10_128_HPKEX25519_CHACHA20POLY1305_SHA256_Ed25519;
+    let epoch = GroupEpoch::from(0u64);
+    let mut astree = ASTree::new(ciphersuite, epoch, &[0u8; 32], RosterIndex::from(2u32));
+    astree.add_member(RosterIndex::from(2u32), RosterIndex::from(3u32));
+
+    // The new leaf's ancestors, and the root add_member just blanked, are
+    // all unpopulated; get_secret must report that instead of panicking.
+    assert_eq!(
+        astree.get_application_secret(epoch, RosterIndex::from(2u32), 0),
+        Err(ASError::TreeNotInitialized)
+    );
+
+    let next_epoch = GroupEpoch::from(1u64);
+    astree.reinit(&[1u8; 32], RosterIndex::from(3u32), next_epoch);
+    assert!(astree
+        .get_application_secret(next_epoch, RosterIndex::from(2u32), 0)
+        .is_ok());
+}
+
+#[test]
+fn test_remove_member_on_pristine_tree_requires_reinit() {
+    let ciphersuite = CipherSuite::MLS10_128_HPKEX25519_CHACHA20POLY1305_SHA256_Ed25519;
+    let epoch = GroupEpoch::from(0u64);
+    let mut astree = ASTree::new(ciphersuite, epoch, &[0u8; 32], RosterIndex::from(2u32));
+
+    // Nothing has been materialized yet, so remove_member's blanked direct
+    // path includes the root and leaves no populated ancestor anywhere in
+    // the tree.
+    assert!(astree.remove_member(RosterIndex::from(1u32)).is_ok());
+    assert_eq!(
+        astree.get_application_secret(epoch, RosterIndex::from(0u32), 0),
+        Err(ASError::TreeNotInitialized)
+    );
+
+    let next_epoch = GroupEpoch::from(1u64);
+    astree.reinit(&[1u8; 32], RosterIndex::from(1u32), next_epoch);
+    assert!(astree
+        .get_application_secret(next_epoch, RosterIndex::from(0u32), 0)
+        .is_ok());
+}
+
+#[test]
+fn test_wrong_epoch_rejected() {
+    let ciphersuite = CipherSuite::MLS10_128_HPKEX25519_CHACHA20POLY1305_SHA256_Ed25519;
+    let epoch = GroupEpoch::from(0u64);
+    let next_epoch = GroupEpoch::from(1u64);
+    let mut astree = ASTree::new(ciphersuite, epoch, &[0u8; 32], RosterIndex::from(2u32));
+
+    assert_eq!(
+        astree.get_application_secret(next_epoch, RosterIndex::from(0u32), 0),
+        Err(ASError::WrongEpoch)
+    );
+
+    astree.reinit(&[1u8; 32], RosterIndex::from(2u32), next_epoch);
+    assert_eq!(astree.epoch(), next_epoch);
+
+    // The epoch this tree was just rekeyed for now rejects requests
+    // carrying the epoch it replaced.
+    assert_eq!(
+        astree.get_application_secret(epoch, RosterIndex::from(0u32), 0),
+        Err(ASError::WrongEpoch)
+    );
+    assert!(astree
+        .get_application_secret(next_epoch, RosterIndex::from(0u32), 0)
+        .is_ok());
+}
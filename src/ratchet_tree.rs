@@ -0,0 +1,319 @@
+// maelstrom
+// Copyright (C) 2020 Raphael Robert
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use crate::astree::Secret;
+use crate::codec::*;
+use crate::crypto::hash::*;
+use crate::crypto::hpke;
+use crate::extensions::*;
+use crate::treemath::*;
+
+#[derive(Debug, PartialEq)]
+pub enum RatchetTreeError {
+    MissingPublicKey,
+    MalformedUpdatePath,
+    DecryptionFailed,
+}
+
+/// One node's contribution to an `UpdatePath`: the node's new HPKE public
+/// key, and its path secret HPKE-encrypted to the public key of the
+/// sibling subtree (the copath node at the same position) so that only
+/// members under that subtree can recover it.
+pub struct UpdatePathNode {
+    pub public_key: hpke::HPKEPublicKey,
+    pub encrypted_path_secret: hpke::HPKECiphertext,
+}
+
+impl Codec for UpdatePathNode {
+    fn encode(&self, buffer: &mut Vec<u8>) -> Result<(), CodecError> {
+        self.public_key.encode(buffer)?;
+        self.encrypted_path_secret.encode(buffer)?;
+        Ok(())
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let public_key = hpke::HPKEPublicKey::decode(cursor)?;
+        let encrypted_path_secret = hpke::HPKECiphertext::decode(cursor)?;
+        Ok(UpdatePathNode {
+            public_key,
+            encrypted_path_secret,
+        })
+    }
+}
+
+/// A commit's encryption-tree update: one `UpdatePathNode` for every node on
+/// the committer's direct path, from its immediate parent up to the root.
+pub struct UpdatePath {
+    pub nodes: Vec<UpdatePathNode>,
+}
+
+impl Codec for UpdatePath {
+    fn encode(&self, buffer: &mut Vec<u8>) -> Result<(), CodecError> {
+        encode_vec(VecSize::VecU32, buffer, &self.nodes)?;
+        Ok(())
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let nodes = decode_vec(VecSize::VecU32, cursor)?;
+        Ok(UpdatePath { nodes })
+    }
+}
+
+struct PathSecret {
+    node: TreeIndex,
+    secret: Secret,
+    public_key: hpke::HPKEPublicKey,
+    private_key: hpke::HPKEPrivateKey,
+}
+
+/// Starting from `leaf_secret`, derive a path secret and HPKE keypair for
+/// `leaf` and every node on its direct path up to the root: each parent's
+/// path secret is `hkdf_expand_label(secret, "path", &[], hash_len)` of its
+/// child's.
+fn derive_path_secrets(
+    ciphersuite: CipherSuite,
+    leaf_secret: &[u8],
+    leaf: RosterIndex,
+    size: RosterIndex,
+) -> Vec<PathSecret> {
+    let hash_len = hash_length(ciphersuite.into());
+    let leaf_in_tree = TreeIndex::from(leaf);
+    let mut dir_path = vec![leaf_in_tree];
+    dir_path.extend(dirpath(leaf_in_tree, size));
+    dir_path.push(root(size));
+
+    let mut path_secrets = Vec::with_capacity(dir_path.len());
+    let mut secret = Secret::new(leaf_secret.to_vec());
+    for (i, node) in dir_path.iter().enumerate() {
+        let (private_key, public_key) = hpke::derive_keypair(ciphersuite, &secret);
+        path_secrets.push(PathSecret {
+            node: *node,
+            secret: secret.clone(),
+            public_key,
+            private_key,
+        });
+        if i + 1 < dir_path.len() {
+            secret = Secret::new(hkdf_expand_label(
+                ciphersuite,
+                &secret,
+                "path",
+                &[],
+                hash_len,
+            ));
+        }
+    }
+    path_secrets
+}
+
+struct RatchetTreeNode {
+    public_key: hpke::HPKEPublicKey,
+    // `None` for a node whose path secret this member hasn't (yet) been
+    // able to decrypt; such a node is only known by its public key.
+    private_key: Option<hpke::HPKEPrivateKey>,
+}
+
+/// The TreeKEM confidentiality tree: a parallel structure to `ASTree` (see
+/// `crate::astree`) that shares the same ratchet-tree indexing but carries
+/// per-node HPKE keypairs instead of message-key ratchets. Committing a
+/// roster change re-keys this tree via `update_path`/`apply_update_path`;
+/// the resulting root secret is the `encryption_secret` an `ASTree` is
+/// seeded or `reinit`ed from.
+pub struct RatchetTree {
+    ciphersuite: CipherSuite,
+    size: RosterIndex,
+    nodes: Vec<Option<RatchetTreeNode>>,
+}
+
+impl RatchetTree {
+    pub fn new(ciphersuite: CipherSuite, size: RosterIndex) -> Self {
+        let num_indices = TreeIndex::from(size).as_usize() - 1;
+        let mut nodes = Vec::with_capacity(num_indices);
+        for _ in 0..num_indices {
+            nodes.push(None);
+        }
+        Self {
+            ciphersuite,
+            size,
+            nodes,
+        }
+    }
+
+    pub fn public_key(&self, node: TreeIndex) -> Option<&hpke::HPKEPublicKey> {
+        self.nodes[node.as_usize()].as_ref().map(|n| &n.public_key)
+    }
+
+    /// Re-key `leaf`'s direct path from a fresh `leaf_secret`, encrypting
+    /// each ancestor's new path secret to the current public key of the
+    /// copath node beneath it (the sibling of its child on the path), and
+    /// install the new keypairs locally. Returns the new
+    /// root path secret alongside the `UpdatePath`, since that secret is
+    /// exactly the `encryption_secret` the caller goes on to seed or
+    /// `reinit` its `ASTree` (see `crate::astree`) with.
+    pub fn update_path(
+        &mut self,
+        leaf: RosterIndex,
+        leaf_secret: &[u8],
+    ) -> Result<(UpdatePath, Secret), RatchetTreeError> {
+        let leaf_in_tree = TreeIndex::from(leaf);
+        let path_secrets = derive_path_secrets(self.ciphersuite, leaf_secret, leaf, self.size);
+        let copath_nodes = copath(leaf_in_tree, self.size);
+        if copath_nodes.len() + 1 != path_secrets.len() {
+            return Err(RatchetTreeError::MalformedUpdatePath);
+        }
+        let root_secret = path_secrets.last().unwrap().secret.clone();
+
+        let mut update_path_nodes = Vec::with_capacity(copath_nodes.len());
+        for (ancestor, copath_node) in path_secrets[1..].iter().zip(copath_nodes.iter()) {
+            let copath_public_key = self
+                .public_key(*copath_node)
+                .ok_or(RatchetTreeError::MissingPublicKey)?;
+            let encrypted_path_secret = hpke::seal(
+                self.ciphersuite,
+                copath_public_key,
+                &[],
+                &[],
+                &ancestor.secret,
+            );
+            update_path_nodes.push(UpdatePathNode {
+                public_key: ancestor.public_key.clone(),
+                encrypted_path_secret,
+            });
+        }
+
+        for path_secret in path_secrets {
+            self.nodes[path_secret.node.as_usize()] = Some(RatchetTreeNode {
+                public_key: path_secret.public_key,
+                private_key: Some(path_secret.private_key),
+            });
+        }
+        Ok((
+            UpdatePath {
+                nodes: update_path_nodes,
+            },
+            root_secret,
+        ))
+    }
+
+    /// Apply an `UpdatePath` from `sender`: decrypt the first ancestor path
+    /// secret this member holds a copath private key for, re-derive every
+    /// secret from there up to the root, and install the resulting
+    /// keypairs. Ancestors below the decryption point are recorded by
+    /// public key only, since this member can't recover their secrets.
+    /// Returns the recovered root path secret, matching `update_path` on
+    /// the committer's side, so it can likewise be handed to `ASTree`.
+    pub fn apply_update_path(
+        &mut self,
+        sender: RosterIndex,
+        update_path: &UpdatePath,
+    ) -> Result<Secret, RatchetTreeError> {
+        let leaf_in_tree = TreeIndex::from(sender);
+        let mut dir_path = vec![leaf_in_tree];
+        dir_path.extend(dirpath(leaf_in_tree, self.size));
+        dir_path.push(root(self.size));
+        let ancestors = &dir_path[1..];
+        let copath_nodes = copath(leaf_in_tree, self.size);
+        if ancestors.len() != update_path.nodes.len() || ancestors.len() != copath_nodes.len() {
+            return Err(RatchetTreeError::MalformedUpdatePath);
+        }
+
+        let hash_len = hash_length(self.ciphersuite.into());
+        let mut secret: Option<Secret> = None;
+        for ((node, update_node), copath_node) in ancestors
+            .iter()
+            .zip(update_path.nodes.iter())
+            .zip(copath_nodes.iter())
+        {
+            if secret.is_none() {
+                if let Some(Some(own_copath_node)) = self.nodes.get(copath_node.as_usize()) {
+                    if let Some(private_key) = &own_copath_node.private_key {
+                        let plaintext = hpke::open(
+                            self.ciphersuite,
+                            private_key,
+                            &[],
+                            &[],
+                            &update_node.encrypted_path_secret,
+                        )
+                        .map_err(|_| RatchetTreeError::DecryptionFailed)?;
+                        secret = Some(Secret::new(plaintext));
+                    }
+                }
+            } else {
+                secret = Some(Secret::new(hkdf_expand_label(
+                    self.ciphersuite,
+                    secret.as_ref().unwrap(),
+                    "path",
+                    &[],
+                    hash_len,
+                )));
+            }
+
+            let (public_key, private_key) = match &secret {
+                Some(s) => {
+                    let (private_key, public_key) = hpke::derive_keypair(self.ciphersuite, s);
+                    (public_key, Some(private_key))
+                }
+                None => (update_node.public_key.clone(), None),
+            };
+            self.nodes[node.as_usize()] = Some(RatchetTreeNode {
+                public_key,
+                private_key,
+            });
+        }
+
+        secret.ok_or(RatchetTreeError::DecryptionFailed)
+    }
+}
+
+#[test]
+fn test_update_path_round_trip_derives_same_root_secret() {
+    let ciphersuite = CipherSuite::MLS10_128_HPKEX25519_CHACHA20POLY1305_SHA256_Ed25519;
+    let size = RosterIndex::from(2u32);
+    let leaf0 = TreeIndex::from(RosterIndex::from(0u32));
+    let leaf1 = TreeIndex::from(RosterIndex::from(1u32));
+
+    // Bootstrap both leaves' HPKE keypairs directly, the way key packages
+    // would populate them in a real handshake, so update_path has a copath
+    // public key to encrypt the new root secret to.
+    let (committer_private, committer_public) = hpke::derive_keypair(ciphersuite, &[1u8; 32]);
+    let (receiver_private, receiver_public) = hpke::derive_keypair(ciphersuite, &[2u8; 32]);
+
+    let mut committer_tree = RatchetTree::new(ciphersuite, size);
+    committer_tree.nodes[leaf0.as_usize()] = Some(RatchetTreeNode {
+        public_key: committer_public.clone(),
+        private_key: Some(committer_private),
+    });
+    committer_tree.nodes[leaf1.as_usize()] = Some(RatchetTreeNode {
+        public_key: receiver_public.clone(),
+        private_key: None,
+    });
+
+    let mut receiver_tree = RatchetTree::new(ciphersuite, size);
+    receiver_tree.nodes[leaf0.as_usize()] = Some(RatchetTreeNode {
+        public_key: committer_public,
+        private_key: None,
+    });
+    receiver_tree.nodes[leaf1.as_usize()] = Some(RatchetTreeNode {
+        public_key: receiver_public,
+        private_key: Some(receiver_private),
+    });
+
+    let (update_path, committer_root_secret) = committer_tree
+        .update_path(RosterIndex::from(0u32), &[3u8; 32])
+        .unwrap();
+    let receiver_root_secret = receiver_tree
+        .apply_update_path(RosterIndex::from(0u32), &update_path)
+        .unwrap();
+
+    assert_eq!(&*committer_root_secret, &*receiver_root_secret);
+}